@@ -12,7 +12,8 @@
 //! - `image`: Identifier of the image (to distinguish different images / applications).
 //! - `device`: Identifier of the hardware / device type.
 //! - `current_version`: The currently installed version of the image. An update image is only provided by the server
-//!    if the version of the image on the server is different to the given version.
+//!   if the version of the image on the server is greater than the given version (or, with `--allow_downgrade`, if
+//!   it merely differs from the given version).
 //!
 //! ## Example
 //!
@@ -69,11 +70,82 @@
 //! - `filename_field_device_type`: The index of the field in the filename that contains the device type.
 //! - `filename_field_version`: The index of the field in the filename that contains the version number.
 //!
-use actix_files;
-use actix_web::{get, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+//! # Publishing Update Images
+//!
+//! Besides placing files into the images directory by hand, update images can also be published remotely via a
+//! `POST /images` endpoint that accepts a `multipart/form-data` upload. Each part is streamed into a temporary file
+//! in the images directory and only renamed into its final place once it has been fully received and its filename
+//! was validated to contain the expected number of separator-delimited fields.
+//!
+//! The endpoint is disabled by default and needs to be enabled by providing an `upload_token` via the
+//! `--upload_token` command line parameter. Requests then need to present this token in an `X-Update-Token` header.
+//! Additionally, the set of IP addresses that are allowed to upload images can be restricted with
+//! `--allowed_upload_ips`, which takes a comma-separated list of exact IP addresses, CIDR ranges (e.g.
+//! `192.168.1.0/24`) or glob patterns with `*` as a wildcard for a whole address segment (e.g. `192.168.1.*`).
+//!
+//! # TLS
+//!
+//! The server can terminate TLS itself instead of relying on a reverse proxy for it. Providing both `--tls_cert` and
+//! `--tls_key` (PEM-encoded certificate chain and private key) makes the server listen for HTTPS instead of plain
+//! HTTP connections. Both parameters need to be given together; starting the server fails with an error if only one
+//! of them is set or if the certificate or key cannot be parsed.
+//!
+//! # Remote Image Backend
+//!
+//! Instead of keeping update images on local disk, the server can be configured with `--images_remote_url` to fetch
+//! them from a remote HTTP(S) source, such as an S3-compatible bucket or another file server. In this mode, the
+//! `update` handler resolves the matching image by fetching a directory listing from the remote source, understanding
+//! either an S3-compatible `ListBucketResult` XML listing or an Apache/nginx-style autoindex HTML listing. The
+//! `Location` of the update response then either points to the remote URL directly (`--images_remote_redirect`) or
+//! to the `/images` endpoint, which streams the bytes back through this server, forwarding the `Range` header so that
+//! resumable SWUpdate downloads keep working.
+//!
+//! # Rate Limiting
+//!
+//! To protect the server when many devices update at the same time, requests to `/` and `/images` can be rate
+//! limited per client IP address with a token-bucket algorithm: `--rate_limit_per_second` configures how many
+//! tokens are refilled per second and `--rate_limit_burst` how many tokens a bucket can hold at most. Every request
+//! consumes one token; if a client's bucket is empty, the server responds with `429 (too many requests)` and a
+//! `Retry-After` header. Rate limiting is disabled when `rate_limit_per_second` is zero, which is the default.
+//!
+//! # Version Comparison
+//!
+//! The version field in an update image's filename and the `current_version` request parameter are parsed as
+//! [semantic versions](https://semver.org/) and an update is only offered if the available version is strictly
+//! greater than the installed one. If more than one matching image has the same, highest valid version, this is
+//! treated as an ambiguous collision and answered with a 500 (internal server error) status code, the same as
+//! before. Pass `--allow_downgrade` to restore the previous behavior of offering an update whenever the version
+//! strings merely differ, without semver parsing.
+//!
+//! # Observability
+//!
+//! Every request to the update endpoint is recorded as a [`tracing`] span carrying the `image`, `device`,
+//! `current_version`, the resolved `target_version` and the request's `outcome`. Requests are additionally logged by
+//! [`tracing_actix_web`]'s `TracingLogger` middleware. These traces are written to stdout. Providing
+//! `--otlp_endpoint` additionally exports them via an OpenTelemetry OTLP exporter to a collector at the given endpoint,
+//! tagged with a `Resource` naming this service, so they can be aggregated into fleet-wide rollout metrics.
+//!
+use actix_multipart::Multipart;
+use actix_web::body::BodyStream;
+use actix_web::middleware::{from_fn, Next};
+use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use clap::Parser;
+use futures_util::StreamExt as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{trace::TracerProvider, Resource};
+use semver::Version;
 use serde_derive::Deserialize;
+use std::collections::HashMap;
 use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing_actix_web::TracingLogger;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
 /// Command line arguments of the application.
 #[derive(Parser, Debug)]
@@ -106,6 +178,58 @@ struct Args {
     /// The index of the field in the filename that contains the version number.
     #[arg(long, default_value = "2")]
     filename_field_version: usize,
+
+    /// The token that needs to be presented in the `X-Update-Token` header to upload update images via
+    /// `POST /images`. The upload endpoint is disabled as long as no token is given.
+    #[arg(long)]
+    upload_token: Option<String>,
+
+    /// Comma-separated list of IP addresses, CIDR ranges (e.g. `192.168.1.0/24`) or glob patterns (e.g.
+    /// `192.168.1.*`) that are allowed to upload update images. If not given, any client that presents the correct
+    /// `upload_token` is allowed to upload.
+    #[arg(long, value_delimiter = ',')]
+    allowed_upload_ips: Option<Vec<String>>,
+
+    /// Path to a PEM-encoded TLS certificate chain. Needs to be given together with `tls_key` to make the server
+    /// listen for HTTPS instead of plain HTTP connections.
+    #[arg(long)]
+    tls_cert: Option<String>,
+
+    /// Path to a PEM-encoded TLS private key. Needs to be given together with `tls_cert` to make the server listen
+    /// for HTTPS instead of plain HTTP connections.
+    #[arg(long)]
+    tls_key: Option<String>,
+
+    /// Base URL of a remote HTTP(S) source (e.g. an S3-compatible bucket or another file server) that the update
+    /// images are fetched from instead of the local `images_directory`.
+    #[arg(long)]
+    images_remote_url: Option<String>,
+
+    /// Redirect clients directly to `images_remote_url` instead of streaming the image bytes back through this
+    /// server. Only has an effect when `images_remote_url` is set.
+    #[arg(long)]
+    images_remote_redirect: bool,
+
+    /// How many requests per second a client IP address is allowed to make to `/` and `/images`. Rate limiting is
+    /// disabled when this is zero.
+    #[arg(long, default_value = "0")]
+    rate_limit_per_second: f64,
+
+    /// How many requests a client IP address can make in a burst before rate limiting kicks in. Values below one
+    /// are treated as one.
+    #[arg(long, default_value = "0")]
+    rate_limit_burst: f64,
+
+    /// Offer an update whenever the available version string merely differs from `current_version`, instead of
+    /// requiring it to be a strictly greater semantic version. Restores the behavior prior to the introduction of
+    /// semver-aware version comparison, including the possibility of "updating" to an older version.
+    #[arg(long)]
+    allow_downgrade: bool,
+
+    /// Endpoint of an OpenTelemetry OTLP collector that update/download request traces are exported to. Traces are
+    /// logged to stdout instead when this is not given.
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
 }
 
 /// Data that needs to be available to the request handlers.
@@ -115,6 +239,12 @@ struct AppData {
     filename_field_image_identifier: usize,
     filename_field_device_type: usize,
     filename_field_version: usize,
+    upload_token: Option<String>,
+    allowed_upload_ips: Option<Vec<String>>,
+    images_remote_url: Option<String>,
+    images_remote_redirect: bool,
+    http_client: awc::Client,
+    allow_downgrade: bool,
 }
 
 /// Parameters of the HTTP request to the update endpoint.
@@ -134,86 +264,654 @@ pub struct UpdateRequest {
     current_version: Option<String>,
 }
 
+/// The number of separator-delimited fields a filename needs to have for all of the configured
+/// `filename_field_*` indices to be valid.
+fn expected_field_count(app_data: &AppData) -> usize {
+    [
+        app_data.filename_field_image_identifier,
+        app_data.filename_field_device_type,
+        app_data.filename_field_version,
+    ]
+    .iter()
+    .max()
+    .unwrap()
+        + 1
+}
+
+/// Lists the filenames of the update images available in the local `images_directory`.
+fn list_local_images(images_directory: &str) -> std::io::Result<Vec<String>> {
+    let paths = fs::read_dir(images_directory)?;
+
+    Ok(paths
+        .filter(|r| r.is_ok())
+        .map(|r| {
+            r.unwrap() // get the paths from the read directory result
+                .file_name() // get the filenames string for the paths
+                .into_string()
+                .unwrap() // already tested that 'is_ok' before
+        })
+        .collect())
+}
+
+/// Lists the filenames of the update images available at the remote `images_remote_url`, by fetching a directory
+/// listing from it. Both an S3-compatible `ListBucketResult` XML listing and an Apache/nginx-style autoindex HTML
+/// listing are understood.
+async fn list_remote_images(client: &awc::Client, base_url: &str) -> Result<Vec<String>, ()> {
+    let mut response = client.get(base_url).send().await.map_err(|_| ())?;
+    let body = response.body().await.map_err(|_| ())?;
+    let listing = String::from_utf8_lossy(&body);
+
+    if listing.contains("<ListBucketResult") {
+        Ok(parse_s3_bucket_listing(&listing))
+    } else {
+        Ok(parse_autoindex_listing(&listing))
+    }
+}
+
+/// Extracts the object keys from an S3-compatible `ListBucketResult` XML listing, skipping keys that denote a
+/// "directory" (i.e. end with a `/`).
+fn parse_s3_bucket_listing(listing: &str) -> Vec<String> {
+    listing
+        .split("<Key>")
+        .skip(1)
+        .filter_map(|segment| segment.split_once("</Key>").map(|(key, _)| key.to_string()))
+        .filter(|key| !key.ends_with('/'))
+        .collect()
+}
+
+/// Extracts the filenames linked to from an Apache/nginx-style autoindex HTML directory listing.
+fn parse_autoindex_listing(listing: &str) -> Vec<String> {
+    listing
+        .split("href=\"")
+        .skip(1)
+        .filter_map(|segment| segment.split_once('"').map(|(href, _)| href.to_string()))
+        .filter(|href| !href.ends_with('/') && !href.starts_with('?') && href != "..")
+        .collect()
+}
+
 /// Request handler for the update endpoint.
 #[get("/")]
+#[tracing::instrument(
+    skip(req, info),
+    fields(
+        image = info.image.as_deref().unwrap_or(""),
+        device = info.device.as_deref().unwrap_or(""),
+        current_version = info.current_version.as_deref().unwrap_or(""),
+        target_version = tracing::field::Empty,
+        outcome = tracing::field::Empty,
+    )
+)]
 async fn update(req: HttpRequest, info: web::Query<UpdateRequest>) -> impl Responder {
     let app_data: &AppData = req.app_data::<AppData>().unwrap();
+    let span = tracing::Span::current();
 
     // check that all parameters are set and respond with a 400 (bad request) if not
     if info.image.is_none() || info.device.is_none() || info.current_version.is_none() {
+        span.record("outcome", "missing parameters");
         return HttpResponse::BadRequest().finish();
     }
 
-    // read all file paths from the images directory
-    let Ok(paths) = fs::read_dir(app_data.images_directory.as_str()) else {
-        return HttpResponse::InternalServerError().finish();
+    // list all available update images, either from the local images directory or from the remote source
+    let files = match app_data.images_remote_url.as_ref() {
+        Some(base_url) => {
+            let Ok(files) = list_remote_images(&app_data.http_client, base_url).await else {
+                span.record("outcome", "error listing remote images");
+                return HttpResponse::InternalServerError().finish();
+            };
+            files
+        }
+        None => {
+            let Ok(files) = list_local_images(app_data.images_directory.as_str()) else {
+                span.record("outcome", "error listing local images");
+                return HttpResponse::InternalServerError().finish();
+            };
+            files
+        }
     };
 
-    // filter the file paths to only include the ones that match the device type
-    let image_files: Vec<_> = paths
-        .filter(|r| r.is_ok())
-        .map(|r| {
-            r.unwrap() // get the paths from the read directory result
-                .file_name() // get the filenames string for the paths
-                .into_string()
-                .unwrap() // already tested that 'is_ok' before
-        })
-        .filter(|f| {
-            let without_extension = f.rsplit_once('.').unwrap().0;
+    // filter the file paths to only include the ones that match the image and device type, keeping the version field
+    // of each candidate alongside its filename
+    let expected_fields = expected_field_count(app_data);
+    let image_files: Vec<(String, String)> = files
+        .into_iter()
+        .filter_map(|f| {
+            // skip entries that don't look like update images at all, rather than panicking on them; a remote
+            // listing is a far less trusted source than the local images directory and routinely contains
+            // extensionless keys, readme/index objects or keys with fewer fields than configured
+            let without_extension = f.rsplit_once('.')?.0;
             let splitted: Vec<&str> = without_extension
                 .split(&app_data.filename_fields_separator)
                 .collect();
-            splitted[app_data.filename_field_image_identifier] == info.image.as_ref().unwrap()
-                && splitted[app_data.filename_field_device_type] == info.device.as_ref().unwrap()
+            if splitted.len() < expected_fields {
+                return None;
+            }
+            if splitted[app_data.filename_field_image_identifier] != info.image.as_ref().unwrap()
+                || splitted[app_data.filename_field_device_type] != info.device.as_ref().unwrap()
+            {
+                return None;
+            }
+            Some((
+                f.clone(),
+                splitted[app_data.filename_field_version].to_string(),
+            ))
         })
         .collect();
 
-    // more than one matching update image available, which is an error
-    if image_files.len() > 1 {
-        return HttpResponse::InternalServerError()
-            .insert_header(("X-Error", "More than one matching update image."))
-            .finish();
+    let selected_file = if app_data.allow_downgrade {
+        // the old any-difference behavior: there must be at most one matching image, and it is offered whenever
+        // its version string differs from the given one, regardless of whether it is actually newer
+        if image_files.len() > 1 {
+            span.record("outcome", "ambiguous: more than one matching image");
+            return HttpResponse::InternalServerError()
+                .insert_header(("X-Error", "More than one matching update image."))
+                .finish();
+        }
+        match image_files.into_iter().next() {
+            Some((file, version)) if version != *info.current_version.as_ref().unwrap() => {
+                span.record("target_version", version.as_str());
+                file
+            }
+            _ => {
+                span.record("outcome", "up to date");
+                return HttpResponse::NotFound().finish();
+            }
+        }
+    } else {
+        // semver-aware behavior: pick the highest valid semantic version among the candidates and only offer it if
+        // it is strictly greater than the installed version
+        let Ok(current_version) = Version::parse(info.current_version.as_ref().unwrap()) else {
+            span.record("outcome", "invalid current_version");
+            return HttpResponse::BadRequest().finish();
+        };
+
+        let mut candidates: Vec<(String, Version)> = image_files
+            .into_iter()
+            .filter_map(|(file, version)| Version::parse(&version).ok().map(|v| (file, v)))
+            .collect();
+        candidates.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+        match candidates.as_slice() {
+            [] => {
+                span.record("outcome", "up to date");
+                return HttpResponse::NotFound().finish();
+            }
+            // compare by precedence (`cmp_precedence`), not `Ord` or `PartialEq`, since two versions that differ only
+            // in build metadata have equal precedence but are told apart by those, and would otherwise let an
+            // arbitrary pick through instead of being reported as ambiguous
+            [(_, version), (_, second), ..]
+                if version.cmp_precedence(second) == std::cmp::Ordering::Equal =>
+            {
+                span.record("outcome", "ambiguous: equal-version collision");
+                return HttpResponse::InternalServerError()
+                    .insert_header((
+                        "X-Error",
+                        "More than one matching update image with the same version.",
+                    ))
+                    .finish();
+            }
+            [(file, version), ..] if *version > current_version => {
+                span.record("target_version", version.to_string().as_str());
+                file.clone()
+            }
+            _ => {
+                span.record("outcome", "up to date");
+                return HttpResponse::NotFound().finish();
+            }
+        }
+    };
+
+    // all parameters are set and update is available, hence we can process the request
+    span.record("outcome", "update offered");
+    let location = match app_data.images_remote_url.as_ref() {
+        Some(base_url) if app_data.images_remote_redirect => {
+            format!("{}/{}", base_url.trim_end_matches('/'), selected_file)
+        }
+        _ => format!("/images/{selected_file}"),
+    };
+    HttpResponse::Found()
+        .insert_header(("Location", location))
+        .finish()
+}
+
+/// Compares two strings in constant time, independent of where they first differ, so that the time taken to reject
+/// an incorrect `upload_token` cannot be used to guess it one character at a time.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
     }
 
-    // no update available if no matching file could be found or the version of the file is the same as the given version
-    if image_files.is_empty()
-        || image_files[0]
-            .rsplit_once('.')
-            .unwrap()
-            .0
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+/// Checks whether the given client address is allowed to upload update images, based on the `allowed_upload_ips`
+/// patterns. Each pattern can either be an exact IP address, a CIDR range (e.g. `192.168.1.0/24`) or a glob pattern
+/// using `*` as a wildcard for a whole address segment (e.g. `192.168.1.*`).
+fn ip_allowed(peer: IpAddr, patterns: &[String]) -> bool {
+    patterns.iter().any(|pattern| {
+        if let Some((network, prefix_len)) = pattern.split_once('/') {
+            let (Ok(network), Ok(prefix_len)) =
+                (network.parse::<IpAddr>(), prefix_len.parse::<u32>())
+            else {
+                return false;
+            };
+            return ip_in_cidr(peer, network, prefix_len);
+        }
+
+        if pattern.contains('*') {
+            return glob_matches(&peer.to_string(), pattern);
+        }
+
+        pattern
+            .parse::<IpAddr>()
+            .map(|ip| ip == peer)
+            .unwrap_or(false)
+    })
+}
+
+/// Checks whether `addr` is contained in the CIDR range given by `network`/`prefix_len`. A `prefix_len` that is out
+/// of range for the address family (greater than 32 for IPv4 or 128 for IPv6) never matches, rather than silently
+/// falling back to a mask that matches every address.
+fn ip_in_cidr(addr: IpAddr, network: IpAddr, prefix_len: u32) -> bool {
+    match (addr, network) {
+        (IpAddr::V4(addr), IpAddr::V4(network)) => {
+            if prefix_len > 32 {
+                return false;
+            }
+            let mask = (!0u32).checked_shl(32 - prefix_len).unwrap_or(0);
+            (u32::from(addr) & mask) == (u32::from(network) & mask)
+        }
+        (IpAddr::V6(addr), IpAddr::V6(network)) => {
+            if prefix_len > 128 {
+                return false;
+            }
+            let mask = (!0u128).checked_shl(128 - prefix_len).unwrap_or(0);
+            (u128::from(addr) & mask) == (u128::from(network) & mask)
+        }
+        _ => false,
+    }
+}
+
+/// Matches `value` against a glob `pattern` where `*` stands for a whole, arbitrary-length segment of the value.
+fn glob_matches(value: &str, pattern: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(first) = parts.next() else {
+        return false;
+    };
+
+    let Some(last) = parts.next_back() else {
+        return value == first;
+    };
+
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+
+    for part in parts {
+        let Some(index) = rest.find(part) else {
+            return false;
+        };
+        rest = &rest[index + part.len()..];
+    }
+
+    // the part after the last `*` needs to end the value, wherever it may occur earlier as well
+    rest.ends_with(last)
+}
+
+/// Request handler for the image upload endpoint.
+///
+/// The request needs to present the configured `upload_token` in an `X-Update-Token` header and, if
+/// `allowed_upload_ips` is configured, originate from one of the allowed client addresses. The body is expected to
+/// be a `multipart/form-data` upload where each part is named after the update image file it should become.
+///
+/// Every part is streamed into a temporary file in the images directory and only renamed into its final place once
+/// it has been fully received and its filename was validated to contain the expected number of separator-delimited
+/// fields (`filename_field_image_identifier`, `filename_field_device_type`, `filename_field_version`).
+#[post("/images")]
+async fn upload(req: HttpRequest, mut payload: Multipart) -> impl Responder {
+    let app_data: &AppData = req.app_data::<AppData>().unwrap();
+
+    // uploads are disabled as long as no upload token was configured
+    let Some(expected_token) = app_data.upload_token.as_ref() else {
+        return HttpResponse::Unauthorized().finish();
+    };
+
+    let token_matches = req
+        .headers()
+        .get("X-Update-Token")
+        .and_then(|value| value.to_str().ok())
+        .map(|token| constant_time_eq(token, expected_token))
+        .unwrap_or(false);
+    if !token_matches {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    if let Some(allowed_ips) = app_data.allowed_upload_ips.as_ref() {
+        let peer_allowed = req
+            .peer_addr()
+            .map(|addr| ip_allowed(addr.ip(), allowed_ips))
+            .unwrap_or(false);
+        if !peer_allowed {
+            return HttpResponse::Forbidden().finish();
+        }
+    }
+
+    while let Some(item) = payload.next().await {
+        let Ok(mut field) = item else {
+            return HttpResponse::BadRequest().finish();
+        };
+
+        let Some(filename) = field
+            .content_disposition()
+            .and_then(|content_disposition| content_disposition.get_filename())
+            .map(str::to_owned)
+        else {
+            return HttpResponse::BadRequest().finish();
+        };
+
+        // reject anything that isn't a bare filename, so that it cannot escape the images directory via path
+        // traversal (`../`) or be turned into an absolute path that `Path::join` would honor verbatim
+        if Path::new(&filename).file_name() != Some(std::ffi::OsStr::new(&filename)) {
+            return HttpResponse::BadRequest()
+                .insert_header(("X-Error", "Filename must not contain path separators."))
+                .finish();
+        }
+
+        let Some(without_extension) = filename.rsplit_once('.').map(|(name, _)| name) else {
+            return HttpResponse::BadRequest().finish();
+        };
+        let fields_count = without_extension
             .split(&app_data.filename_fields_separator)
-            .collect::<Vec<&str>>()[app_data.filename_field_version]
-            == info.current_version.as_ref().unwrap()
-    {
+            .count();
+        if fields_count < expected_field_count(app_data) {
+            return HttpResponse::BadRequest()
+                .insert_header(("X-Error", "Filename does not contain the expected fields."))
+                .finish();
+        }
+
+        let final_path = Path::new(&app_data.images_directory).join(&filename);
+        let temp_path = Path::new(&app_data.images_directory).join(format!("{filename}.part"));
+
+        let Ok(mut file) = fs::File::create(&temp_path) else {
+            return HttpResponse::InternalServerError().finish();
+        };
+        while let Some(chunk) = field.next().await {
+            let Ok(chunk) = chunk else {
+                let _ = fs::remove_file(&temp_path);
+                return HttpResponse::BadRequest().finish();
+            };
+            if file.write_all(&chunk).is_err() {
+                let _ = fs::remove_file(&temp_path);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+
+        if fs::rename(&temp_path, &final_path).is_err() {
+            let _ = fs::remove_file(&temp_path);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    HttpResponse::Ok().finish()
+}
+
+/// Request handler that streams an update image from the remote source back through this server, forwarding the
+/// `Range` header so that resumable SWUpdate downloads keep working. Registered in place of the local file service
+/// whenever `images_remote_url` is configured, also when `images_remote_redirect` is set.
+#[get("/images/{filename:.*}")]
+async fn proxy_remote_image(req: HttpRequest, filename: web::Path<String>) -> impl Responder {
+    let app_data: &AppData = req.app_data::<AppData>().unwrap();
+
+    let Some(base_url) = app_data.images_remote_url.as_ref() else {
         return HttpResponse::NotFound().finish();
+    };
+
+    // reject path traversal so that a request cannot make this server issue GETs outside of the configured remote
+    // source, the same way actix_files sanitizes the path when serving images from the local images directory
+    if filename
+        .split('/')
+        .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+    {
+        return HttpResponse::BadRequest().finish();
     }
 
-    // all parameters are set and update is available, hence we can process the request
-    HttpResponse::Found()
-        .insert_header(("Location", format!("/images/{}", image_files[0])))
-        .finish()
+    let url = format!("{}/{}", base_url.trim_end_matches('/'), filename.as_str());
+    let mut upstream_request = app_data.http_client.get(&url);
+    if let Some(range) = req.headers().get("Range") {
+        upstream_request = upstream_request.insert_header(("Range", range.clone()));
+    }
+
+    let Ok(upstream_response) = upstream_request.send().await else {
+        return HttpResponse::BadGateway().finish();
+    };
+
+    let mut client_response = HttpResponse::build(upstream_response.status());
+    for header in [
+        "Content-Type",
+        "Content-Length",
+        "Content-Range",
+        "Accept-Ranges",
+    ] {
+        if let Some(value) = upstream_response.headers().get(header) {
+            client_response.insert_header((header, value.clone()));
+        }
+    }
+
+    client_response.body(BodyStream::new(upstream_response))
+}
+
+/// How long a client's token bucket can stay unused before it is evicted to bound the memory used by the rate
+/// limiter.
+const RATE_LIMITER_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How often the rate limiter is checked for idle buckets to evict.
+const RATE_LIMITER_EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Per-client-IP token-bucket rate limiter. Each bucket holds up to `burst` tokens and refills at `rate`
+/// tokens/second; a request is let through if at least one token is available.
+struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            // a bucket that cannot hold a whole token would refuse every request forever
+            burst: burst.max(1.0),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Tries to consume one token from the bucket of the given client IP address. Returns `Ok(())` if the request
+    /// may proceed, or `Err(seconds_to_wait)` with the number of seconds until a token becomes available again.
+    fn try_acquire(&self, ip: IpAddr) -> Result<(), f64> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        let (tokens, last_refill) = buckets.entry(ip).or_insert((self.burst, now));
+
+        let elapsed = now.duration_since(*last_refill).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.rate).min(self.burst);
+        *last_refill = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(())
+        } else {
+            Err((1.0 - *tokens) / self.rate)
+        }
+    }
+
+    /// Removes buckets of clients that have not made a request for at least `idle_timeout` to bound the memory used
+    /// by the rate limiter.
+    fn evict_idle(&self, idle_timeout: Duration) {
+        let now = Instant::now();
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, (_, last_refill)| now.duration_since(*last_refill) < idle_timeout);
+    }
+}
+
+/// Middleware that rate limits requests per client IP address using the `RateLimiter` stored in the app data. Does
+/// nothing if no rate limiter is configured or rate limiting is disabled (`rate` is zero).
+async fn rate_limit(
+    req: actix_web::dev::ServiceRequest,
+    next: Next<impl actix_web::body::MessageBody + 'static>,
+) -> Result<actix_web::dev::ServiceResponse<impl actix_web::body::MessageBody>, actix_web::Error> {
+    let limiter = req.app_data::<Arc<RateLimiter>>().cloned();
+    let peer_ip = req.peer_addr().map(|addr| addr.ip());
+
+    if let (Some(limiter), Some(peer_ip)) = (limiter, peer_ip) {
+        if limiter.rate > 0.0 {
+            if let Err(retry_after) = limiter.try_acquire(peer_ip) {
+                let response = HttpResponse::TooManyRequests()
+                    .insert_header(("Retry-After", retry_after.ceil().to_string()))
+                    .finish();
+                return Ok(req
+                    .into_response(response)
+                    .map_into_boxed_body()
+                    .map_into_right_body());
+            }
+        }
+    }
+
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+/// Builds the `rustls` server configuration from the PEM-encoded certificate chain and private key at the given
+/// paths.
+fn load_tls_config(cert_path: &str, key_path: &str) -> std::io::Result<rustls::ServerConfig> {
+    let cert_file = &mut std::io::BufReader::new(fs::File::open(cert_path)?);
+    let key_file = &mut std::io::BufReader::new(fs::File::open(key_path)?);
+
+    let cert_chain = rustls_pemfile::certs(cert_file)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    let key = rustls_pemfile::private_key(key_file)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "no private key found in tls_key file",
+            )
+        })?;
+
+    rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Initializes the global `tracing` subscriber. When `otlp_endpoint` is given, spans are exported via an
+/// OpenTelemetry OTLP exporter to the collector at that endpoint, tagged with a `Resource` naming this service. In
+/// either case, traces are logged to stdout.
+fn init_tracing(otlp_endpoint: Option<&str>) {
+    let Some(otlp_endpoint) = otlp_endpoint else {
+        tracing_subscriber::fmt::init();
+        return;
+    };
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(otlp_endpoint)
+        .build()
+        .expect("failed to build OTLP span exporter");
+
+    let resource = Resource::new(vec![KeyValue::new("service.name", "swupdate-httpd")]);
+
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_resource(resource)
+        .build();
+
+    let tracer = opentelemetry::trace::TracerProvider::tracer(&provider, "swupdate-httpd");
+    opentelemetry::global::set_tracer_provider(provider);
+
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .init();
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let args = Args::parse();
 
-    HttpServer::new(move || {
-        App::new()
+    init_tracing(args.otlp_endpoint.as_deref());
+
+    if args.tls_cert.is_some() != args.tls_key.is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "tls_cert and tls_key need to be given together",
+        ));
+    }
+
+    let tls_config = match (args.tls_cert.as_ref(), args.tls_key.as_ref()) {
+        (Some(cert_path), Some(key_path)) => Some(load_tls_config(cert_path, key_path)?),
+        _ => None,
+    };
+
+    let rate_limiter = Arc::new(RateLimiter::new(
+        args.rate_limit_per_second,
+        args.rate_limit_burst,
+    ));
+    if args.rate_limit_per_second > 0.0 {
+        let rate_limiter = rate_limiter.clone();
+        actix_web::rt::spawn(async move {
+            loop {
+                actix_web::rt::time::sleep(RATE_LIMITER_EVICTION_INTERVAL).await;
+                rate_limiter.evict_idle(RATE_LIMITER_IDLE_TIMEOUT);
+            }
+        });
+    }
+
+    let server = HttpServer::new(move || {
+        let app = App::new()
             .app_data(AppData {
                 images_directory: args.images_directory.clone(),
                 filename_fields_separator: args.filename_fields_separator.clone(),
                 filename_field_image_identifier: args.filename_field_image_identifier,
                 filename_field_device_type: args.filename_field_device_type,
                 filename_field_version: args.filename_field_version,
+                upload_token: args.upload_token.clone(),
+                allowed_upload_ips: args.allowed_upload_ips.clone(),
+                images_remote_url: args.images_remote_url.clone(),
+                images_remote_redirect: args.images_remote_redirect,
+                http_client: awc::Client::default(),
+                allow_downgrade: args.allow_downgrade,
             })
+            .app_data(rate_limiter.clone())
+            .wrap(from_fn(rate_limit))
+            .wrap(TracingLogger::default())
             .service(update)
-            .service(
+            .service(upload);
+
+        // serve update images either from the remote source or from the local images directory, depending on
+        // whether images_remote_url is configured
+        if args.images_remote_url.is_some() {
+            app.service(proxy_remote_image)
+        } else {
+            app.service(
                 actix_files::Files::new("/images", args.images_directory.as_str())
                     .show_files_listing(),
             )
-    })
-    .bind((args.listen_ip, args.listen_port))?
-    .run()
-    .await
+        }
+    });
+
+    match tls_config {
+        Some(tls_config) => {
+            server
+                .bind_rustls_0_23((args.listen_ip, args.listen_port), tls_config)?
+                .run()
+                .await
+        }
+        None => server.bind((args.listen_ip, args.listen_port))?.run().await,
+    }
 }